@@ -2,9 +2,11 @@
 
 extern crate alloc;
 
-use ahash::AHasher;
-use alloc::{collections::BTreeMap, string::String, vec::Vec};
-use core::hash::{Hash, Hasher};
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::marker::PhantomData;
+use core::ops::Range;
+use serde::{Deserialize, Serialize};
+use spin::Mutex;
 
 lazy_static::lazy_static! {
     /// The ln table with value ln(x)<<44 for x in [0,65536).
@@ -12,31 +14,224 @@ lazy_static::lazy_static! {
         (0..65536).map(|i| (-((i as f64 / 65536.0).ln() * ((1u64 << 44) as f64)).round()) as u64).collect();
 }
 
+/// A hash function usable to draw CRUSH placement decisions.
+///
+/// Placement is only reproducible across independent nodes if they all use the
+/// same, fully-specified hasher: a generic string hasher such as `ahash` varies
+/// its output across crate versions, CPU features, and builds, which would let
+/// two nodes computing placement independently disagree on where a PG lives.
+pub trait CrushHasher {
+    /// Hash a bucket item's `name` together with the `key` (pgid) and draw `index`
+    /// into a stable 32-bit digest.
+    fn hash(name: &str, key: u32, index: u32) -> u32;
+}
+
+/// The default hasher: a fixed rjenkins1-style integer mix modelled on Ceph's
+/// `crush_hash`, with the item name folded to 32 bits by FNV-1a first. It uses
+/// Ceph's seed and mixing round but does not reproduce Ceph's digests.
+///
+/// Unlike a generic string hasher, this is specified bit-for-bit and is stable
+/// across crate versions, CPU features, and builds.
+#[derive(Default, Clone, Copy)]
+pub struct JenkinsHash;
+
+impl CrushHasher for JenkinsHash {
+    fn hash(name: &str, key: u32, index: u32) -> u32 {
+        jenkins_hash3(fnv1a_32(name), key, index)
+    }
+}
+
+/// A 32-bit FNV-1a hash, used to fold a bucket item's name into an integer
+/// before mixing it with the jenkins hash below.
+fn fnv1a_32(s: &str) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    for &b in s.as_bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Ceph's `crush_hashmix`: the shift-xor-subtract mixing round shared by
+/// `crush_hash32_rjenkins1_3`.
+fn crush_hashmix(a: &mut u32, b: &mut u32, c: &mut u32) {
+    *a = a.wrapping_sub(*b).wrapping_sub(*c) ^ (*c >> 13);
+    *b = b.wrapping_sub(*c).wrapping_sub(*a) ^ (*a << 8);
+    *c = c.wrapping_sub(*a).wrapping_sub(*b) ^ (*b >> 13);
+    *a = a.wrapping_sub(*b).wrapping_sub(*c) ^ (*c >> 12);
+    *b = b.wrapping_sub(*c).wrapping_sub(*a) ^ (*a << 16);
+    *c = c.wrapping_sub(*a).wrapping_sub(*b) ^ (*b >> 5);
+    *a = a.wrapping_sub(*b).wrapping_sub(*c) ^ (*c >> 3);
+    *b = b.wrapping_sub(*c).wrapping_sub(*a) ^ (*a << 10);
+    *c = c.wrapping_sub(*a).wrapping_sub(*b) ^ (*b >> 15);
+}
+
+/// A three-input hash in the style of Ceph's `crush_hash32_rjenkins1_3`: seed
+/// `c` with `CRUSH_HASH_SEED + index`, fold `a`, `b` and `c` through two rounds
+/// of [`crush_hashmix`] and return the final `c` accumulator. Its digests are
+/// not those of Ceph's function.
+fn jenkins_hash3(a: u32, b: u32, index: u32) -> u32 {
+    const CRUSH_HASH_SEED: u32 = 1315423911;
+    let mut a = a;
+    let mut b = b;
+    let mut c = CRUSH_HASH_SEED.wrapping_add(index);
+    crush_hashmix(&mut a, &mut b, &mut c);
+    crush_hashmix(&mut a, &mut b, &mut c);
+    c
+}
+
 /// The CRUSH algorithm.
-#[derive(Default, Clone)]
-pub struct Crush {
-    root: Node,
+///
+/// Generic over the [`CrushHasher`] used to draw placement decisions; defaults
+/// to [`JenkinsHash`] so that placement is stable and reproducible out of the box.
+///
+/// Carries a `version` bumped on every [`Crush::commit`], and a staging area
+/// ([`Crush::stage_weight`]/[`Crush::stage_inout`]) so a batch of edits can be
+/// applied atomically instead of perturbing placement one call at a time.
+///
+/// The root is held behind an `Arc` so mutators can clone-on-write just the
+/// path from the root to the changed node: see [`Crush::snapshot`].
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = ""))]
+pub struct Crush<H: CrushHasher = JenkinsHash> {
+    root: Arc<Node>,
+    version: u64,
+    #[serde(skip)]
+    staged: Vec<StagedChange>,
+    #[serde(skip)]
+    _hasher: PhantomData<H>,
+}
+
+impl<H: CrushHasher> Default for Crush<H> {
+    fn default() -> Self {
+        Crush {
+            root: Arc::new(Node::default()),
+            version: 0,
+            staged: Vec::new(),
+            _hasher: PhantomData,
+        }
+    }
+}
+
+impl<H: CrushHasher> Clone for Crush<H> {
+    fn clone(&self) -> Self {
+        Crush {
+            root: self.root.clone(),
+            version: self.version,
+            staged: self.staged.clone(),
+            _hasher: PhantomData,
+        }
+    }
+}
+
+/// A weight or IN/OUT change accumulated by [`Crush::stage_weight`] or
+/// [`Crush::stage_inout`], applied atomically by [`Crush::commit`].
+#[derive(Clone)]
+enum StagedChange {
+    Weight { path: String, weight: i64 },
+    Inout { path: String, out: bool },
+}
+
+/// A single PG relocation detected by [`Crush::diff`]: `pgid` moved from
+/// `from` to `to`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PgMove {
+    pub pgid: u32,
+    pub from: String,
+    pub to: String,
 }
 
 /// A node in cluster map.
 ///
 /// Maybe root / row / rack / host / osd.
-#[derive(Default, Clone)]
+///
+/// `Send + Sync` (the tree-bucket cache uses a spinlock, not a `RefCell`) so
+/// an `Arc<Node>` can be shared between a writer and any number of readers
+/// holding a [`CrushSnapshot`]. Reads through [`BucketKind::Straw2`] buckets
+/// never block each other; every draw through a [`BucketKind::Tree`] bucket
+/// holds `tree_cache`'s spinlock for the whole descent (and builds the cache
+/// under it on the first draw after an invalidation), so concurrent readers
+/// of the same `Tree` bucket serialize on it, though never with the writer
+/// itself.
+#[derive(Default, Serialize, Deserialize)]
 struct Node {
     weight: u64,
     out: bool,
-    children: BTreeMap<String, Node>,
+    children: BTreeMap<String, Arc<Node>>,
+    bucket: BucketKind,
+    /// Cached weighted binary tree over `children`, used by `choose_tree`.
+    /// Invalidated (set to `None`) whenever this node's weight changes.
+    #[serde(skip)]
+    tree_cache: Mutex<Option<TreeCache>>,
 }
 
-impl Crush {
-    /// Add weight to a node.
+impl Clone for Node {
+    /// Clones the node itself; `children` only clones the `Arc` pointers
+    /// (cheap, shares subtrees) and `tree_cache` is dropped, not copied, so
+    /// the clone rebuilds it lazily on next use.
+    fn clone(&self) -> Self {
+        Node {
+            weight: self.weight,
+            out: self.out,
+            children: self.children.clone(),
+            bucket: self.bucket,
+            tree_cache: Mutex::new(None),
+        }
+    }
+}
+
+/// The bucket selection strategy for a [`Node`]'s children.
+///
+/// `Straw2` scans every child and is exact but O(n); `Tree` organizes children
+/// as a weighted binary tree for O(log n) selection. `Tree` draws are not as
+/// uniform as `Straw2`'s: within a single bucket, the modulo bias of reducing
+/// the 32-bit hash to each split's weight sum can skew individual children's
+/// odds slightly versus their exact weight share. Large buckets (thousands of items) should opt into
+/// `Tree`; small buckets can keep the default `Straw2`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BucketKind {
+    #[default]
+    Straw2,
+    Tree,
+}
+
+/// A weighted binary tree cached over a node's children, padded to the next
+/// power of two so interior nodes can be indexed as `2*i`/`2*i+1`.
+#[derive(Clone)]
+struct TreeCache {
+    /// Children names in the same sorted order used to build the tree.
+    order: Vec<String>,
+    /// Subtree weight sums, 1-indexed: `weight[i] = weight[2i] + weight[2i+1]`.
+    weight: Vec<u64>,
+    /// Number of leaf slots (a power of two, >= `order.len()`).
+    n: usize,
+}
+
+impl TreeCache {
+    fn build(children: &BTreeMap<String, Arc<Node>>) -> Self {
+        let order: Vec<String> = children.keys().cloned().collect();
+        let n = order.len().next_power_of_two().max(1);
+        let mut weight = alloc::vec![0u64; 2 * n];
+        for (i, name) in order.iter().enumerate() {
+            weight[n + i] = children[name].weight;
+        }
+        for i in (1..n).rev() {
+            weight[i] = weight[2 * i] + weight[2 * i + 1];
+        }
+        TreeCache { order, weight, n }
+    }
+}
+
+impl<H: CrushHasher> Crush<H> {
+    /// Add weight to a node. Clones only the nodes along `path` from the root
+    /// (copy-on-write), so existing [`Crush::snapshot`]s keep seeing the old tree.
     pub fn add_weight(&mut self, path: &str, weight: i64) {
-        self.root.add_weight(path, weight);
+        Arc::make_mut(&mut self.root).add_weight(path, weight);
     }
 
     /// Locate a node by `pgid`.
     pub fn locate(&self, pgid: u32) -> String {
-        self.select(pgid, 1).into_iter().next().unwrap()
+        locate_in::<H>(&self.root, pgid)
     }
 
     /// Return the total weight of the cluster.
@@ -49,9 +244,10 @@ impl Crush {
         self.root.get(path).weight
     }
 
-    /// Set a node IN/OUT.
+    /// Set a node IN/OUT. Clones only the nodes along `path` from the root
+    /// (copy-on-write), so existing [`Crush::snapshot`]s keep seeing the old tree.
     pub fn set_inout(&mut self, path: &str, out: bool) {
-        self.root.get_mut(path).out = out;
+        Arc::make_mut(&mut self.root).get_mut(path).out = out;
     }
 
     /// Get IN/OUT of a node.
@@ -59,40 +255,494 @@ impl Crush {
         self.root.get(path).out
     }
 
-    /// Select `num` targets accoding to `pgid`.
-    pub fn select(&self, pgid: u32, num: u32) -> Vec<String> {
-        let mut targets = Vec::<String>::new();
-        let mut failure_count = 0;
-        for r in 0..num {
-            let mut node = &self.root;
-            let mut local_failure = 0;
-            let mut fullname = String::new();
-            loop {
-                let name = node.choose(pgid, r + failure_count);
-                if !fullname.is_empty() {
-                    fullname += "/";
-                }
-                fullname += name;
-                let child = &node.children[name];
-                if !child.children.is_empty() {
-                    node = child;
-                    continue;
+    /// Set the bucket selection strategy for a node's children. Large buckets
+    /// (thousands of items) should opt into [`BucketKind::Tree`] for O(log n)
+    /// selection; small buckets can keep the default [`BucketKind::Straw2`].
+    pub fn set_bucket_kind(&mut self, path: &str, kind: BucketKind) {
+        Arc::make_mut(&mut self.root).get_mut(path).bucket = kind;
+    }
+
+    /// Get the bucket selection strategy for a node's children.
+    pub fn get_bucket_kind(&self, path: &str) -> BucketKind {
+        self.root.get(path).bucket
+    }
+
+    /// Return the map's version, bumped once on every [`Crush::commit`].
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Stage a weight change without affecting `locate`/`select`; applied by
+    /// the next [`Crush::commit`].
+    pub fn stage_weight(&mut self, path: &str, weight: i64) {
+        self.staged.push(StagedChange::Weight {
+            path: path.into(),
+            weight,
+        });
+    }
+
+    /// Stage an IN/OUT change without affecting `locate`/`select`; applied by
+    /// the next [`Crush::commit`].
+    pub fn stage_inout(&mut self, path: &str, out: bool) {
+        self.staged.push(StagedChange::Inout {
+            path: path.into(),
+            out,
+        });
+    }
+
+    /// Atomically apply every staged change and bump [`Crush::version`].
+    pub fn commit(&mut self) {
+        for change in self.staged.drain(..) {
+            match change {
+                StagedChange::Weight { path, weight } => {
+                    Arc::make_mut(&mut self.root).add_weight(&path, weight)
                 }
-                if !child.out && !targets.contains(&fullname) {
-                    // found one
-                    break;
+                StagedChange::Inout { path, out } => {
+                    Arc::make_mut(&mut self.root).get_mut(&path).out = out
                 }
+            }
+        }
+        self.version += 1;
+    }
+
+    /// Report which PGs in `pgids` would relocate between `self` and `other`,
+    /// so an operator can preview data movement before committing.
+    pub fn diff(&self, other: &Crush<H>, pgids: Range<u32>) -> Vec<PgMove> {
+        pgids
+            .filter_map(|pgid| {
+                let from = self.locate(pgid);
+                let to = other.locate(pgid);
+                (from != to).then_some(PgMove { pgid, from, to })
+            })
+            .collect()
+    }
+
+    /// Select `num` targets accoding to `pgid`.
+    pub fn select(&self, pgid: u32, num: u32) -> Vec<String> {
+        select_in::<H>(&self.root, pgid, num)
+    }
+
+    /// Select `num` targets accoding to `pgid`, guaranteeing each result descends
+    /// from a distinct ancestor at `domain_level` (e.g. 1 = rack, 2 = host).
+    ///
+    /// This is CRUSH's `chooseleaf` behavior: replicas are spread across
+    /// failure domains first, then a leaf is drawn within the chosen domain.
+    /// A degraded cluster may yield fewer than `num` targets once the total
+    /// retry budget is exhausted, rather than looping forever.
+    pub fn select_failure_domain(&self, pgid: u32, num: u32, domain_level: usize) -> Vec<String> {
+        select_failure_domain_in::<H>(&self.root, pgid, num, domain_level)
+    }
+
+    /// Select targets by running a [`Rule`] of `take`/`choose`/`chooseleaf`/`emit` steps.
+    ///
+    /// This replaces the hard-coded "start at root, descend to leaves" policy of
+    /// [`Crush::select`] with the small bytecode Ceph's mapper runs, so operators
+    /// can express things like "take the ssd subtree, chooseleaf 3 osds, emit".
+    /// Only steps reached by an `emit` step contribute to the result.
+    pub fn select_with_rule(&self, pgid: u32, rule: &Rule) -> Vec<String> {
+        select_with_rule_in::<H>(&self.root, pgid, rule)
+    }
+
+    /// Take a cheap, immutable, `Arc`-backed snapshot of the current map.
+    ///
+    /// A snapshot pins the version it was taken from: readers can keep
+    /// placing PGs against it while a writer mutates `self` through
+    /// copy-on-write. Mutations never touch nodes a live snapshot is still
+    /// pointing at, so the writer never blocks a reader. Reads are wait-free
+    /// through [`BucketKind::Straw2`] buckets; every draw through a
+    /// [`BucketKind::Tree`] bucket takes that bucket's spinlock, so readers of
+    /// the same `Tree` bucket contend with each other there (never with the
+    /// writer).
+    pub fn snapshot(&self) -> CrushSnapshot<H> {
+        CrushSnapshot {
+            root: self.root.clone(),
+            version: self.version,
+            _hasher: PhantomData,
+        }
+    }
+}
+
+/// A cheap, immutable, `Arc`-backed view of a [`Crush`] map.
+///
+/// See [`Crush::snapshot`].
+pub struct CrushSnapshot<H: CrushHasher = JenkinsHash> {
+    root: Arc<Node>,
+    version: u64,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: CrushHasher> Clone for CrushSnapshot<H> {
+    fn clone(&self) -> Self {
+        CrushSnapshot {
+            root: self.root.clone(),
+            version: self.version,
+            _hasher: PhantomData,
+        }
+    }
+}
+
+impl<H: CrushHasher> CrushSnapshot<H> {
+    /// The map version this snapshot was taken from.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Return the total weight of the cluster as of this snapshot.
+    pub fn total_weight(&self) -> u64 {
+        self.root.weight
+    }
+
+    /// Get the weight of a node as of this snapshot.
+    pub fn get_weight(&self, path: &str) -> u64 {
+        self.root.get(path).weight
+    }
+
+    /// Get IN/OUT of a node as of this snapshot.
+    pub fn get_inout(&self, path: &str) -> bool {
+        self.root.get(path).out
+    }
+
+    /// Get the bucket selection strategy for a node's children as of this snapshot.
+    pub fn get_bucket_kind(&self, path: &str) -> BucketKind {
+        self.root.get(path).bucket
+    }
+
+    /// Locate a node by `pgid`, as of this snapshot.
+    pub fn locate(&self, pgid: u32) -> String {
+        locate_in::<H>(&self.root, pgid)
+    }
+
+    /// Select `num` targets accoding to `pgid`, as of this snapshot. See [`Crush::select`].
+    pub fn select(&self, pgid: u32, num: u32) -> Vec<String> {
+        select_in::<H>(&self.root, pgid, num)
+    }
+
+    /// See [`Crush::select_failure_domain`].
+    pub fn select_failure_domain(&self, pgid: u32, num: u32, domain_level: usize) -> Vec<String> {
+        select_failure_domain_in::<H>(&self.root, pgid, num, domain_level)
+    }
+
+    /// See [`Crush::select_with_rule`].
+    pub fn select_with_rule(&self, pgid: u32, rule: &Rule) -> Vec<String> {
+        select_with_rule_in::<H>(&self.root, pgid, rule)
+    }
+}
+
+/// Locate a node by `pgid`, shared by [`Crush::locate`] and [`CrushSnapshot::locate`].
+fn locate_in<H: CrushHasher>(root: &Node, pgid: u32) -> String {
+    select_in::<H>(root, pgid, 1).into_iter().next().unwrap()
+}
+
+/// Select `num` targets accoding to `pgid`, shared by [`Crush::select`] and
+/// [`CrushSnapshot::select`].
+fn select_in<H: CrushHasher>(root: &Node, pgid: u32, num: u32) -> Vec<String> {
+    let mut targets = Vec::<String>::new();
+    let mut failure_count = 0;
+    for r in 0..num {
+        let mut node = root;
+        let mut local_failure = 0;
+        let mut fullname = String::new();
+        loop {
+            let name = node.choose::<H>(&fullname, pgid, r + failure_count);
+            if !fullname.is_empty() {
+                fullname += "/";
+            }
+            fullname += name;
+            let child: &Node = node.children[name].as_ref();
+            if !child.children.is_empty() {
+                node = child;
+                continue;
+            }
+            if !child.out && !targets.contains(&fullname) {
+                // found one
+                break;
+            }
+            failure_count += 1;
+            local_failure += 1;
+            if local_failure > 3 {
+                node = root;
+                local_failure = 0;
+                fullname.clear();
+            }
+        }
+        targets.push(fullname);
+    }
+    targets
+}
+
+/// Select `num` targets accoding to `pgid`, guaranteeing each result descends
+/// from a distinct ancestor at `domain_level`. Shared by
+/// [`Crush::select_failure_domain`] and [`CrushSnapshot::select_failure_domain`].
+fn select_failure_domain_in<H: CrushHasher>(
+    root: &Node,
+    pgid: u32,
+    num: u32,
+    domain_level: usize,
+) -> Vec<String> {
+    let mut targets = Vec::<String>::new();
+    let mut used_domains = Vec::<String>::new();
+    let mut failure_count = 0;
+    let max_tries = num.saturating_mul(64).max(64);
+    let mut total_tries = 0;
+    'replica: for r in 0..num {
+        let mut local_failure = 0;
+        let mut node = root;
+        let mut fullname = String::new();
+        let mut domain = String::new();
+        let mut depth = 0usize;
+        loop {
+            if total_tries >= max_tries {
+                break 'replica;
+            }
+            total_tries += 1;
+            let name = node.choose::<H>(&fullname, pgid, r + failure_count);
+            let mut candidate = fullname.clone();
+            if !candidate.is_empty() {
+                candidate += "/";
+            }
+            candidate += name;
+            let child: &Node = node.children[name].as_ref();
+            let next_depth = depth + 1;
+
+            if next_depth == domain_level && used_domains.contains(&candidate) {
+                // this domain was already used by an earlier replica: retry
+                // locally within `node` (redraw with a bumped index), the
+                // same way `draw_leaf` retries within a bucket, before
+                // giving up and restarting the whole domain draw from root.
                 failure_count += 1;
                 local_failure += 1;
                 if local_failure > 3 {
-                    node = &self.root;
                     local_failure = 0;
+                    node = root;
                     fullname.clear();
+                    depth = 0;
+                }
+                continue;
+            }
+
+            if !child.children.is_empty() {
+                depth = next_depth;
+                if depth == domain_level {
+                    domain = candidate.clone();
+                }
+                fullname = candidate;
+                node = child;
+                continue;
+            }
+            if !child.out && !targets.contains(&candidate) {
+                // found one
+                if next_depth == domain_level {
+                    domain = candidate.clone();
                 }
+                used_domains.push(domain.clone());
+                fullname = candidate;
+                break;
+            }
+            // leaf rejected (out or already used): retry locally within
+            // `node` before giving up and restarting the whole domain draw.
+            failure_count += 1;
+            local_failure += 1;
+            if local_failure > 3 {
+                local_failure = 0;
+                node = root;
+                fullname.clear();
+                depth = 0;
             }
-            targets.push(fullname);
         }
-        targets
+        targets.push(fullname);
+    }
+    targets
+}
+
+/// Select targets by running a [`Rule`]. Shared by [`Crush::select_with_rule`]
+/// and [`CrushSnapshot::select_with_rule`].
+fn select_with_rule_in<H: CrushHasher>(root: &Node, pgid: u32, rule: &Rule) -> Vec<String> {
+    let mut targets = Vec::<String>::new();
+    let mut working: Vec<(&Node, String)> = Vec::new();
+    for step in &rule.steps {
+        match step {
+            Step::Take(path) => {
+                working = alloc::vec![(root.get(path), String::from(path.as_str()))];
+            }
+            Step::Choose { count, level } => {
+                let mut next = Vec::new();
+                for (node, path) in &working {
+                    next.extend(draw_buckets::<H>(node, path, *level, pgid, *count));
+                }
+                working = next;
+            }
+            Step::ChooseLeaf { count, level } => {
+                let mut next = Vec::new();
+                for (node, path) in &working {
+                    for (i, (bucket, bucket_path)) in
+                        draw_buckets::<H>(node, path, *level, pgid, *count)
+                            .into_iter()
+                            .enumerate()
+                    {
+                        if let Some(leaf) =
+                            draw_leaf::<H>(bucket, &bucket_path, pgid, i as u32, &targets)
+                        {
+                            next.push(leaf);
+                        }
+                    }
+                }
+                working = next;
+            }
+            Step::Emit => {
+                targets.extend(working.drain(..).map(|(_, path)| path));
+            }
+        }
+    }
+    targets
+}
+
+/// Draw up to `count` distinct buckets reachable `level` steps below `node`,
+/// retrying on duplicates with a bounded total-tries budget.
+fn draw_buckets<'a, H: CrushHasher>(
+    node: &'a Node,
+    base_path: &str,
+    level: usize,
+    pgid: u32,
+    count: u32,
+) -> Vec<(&'a Node, String)> {
+    let mut result = Vec::<(&Node, String)>::new();
+    let max_tries = count.saturating_mul(64).max(64);
+    let mut index = 0;
+    let mut tries = 0;
+    while (result.len() as u32) < count && tries < max_tries {
+        tries += 1;
+        let mut cur = node;
+        let mut path = String::from(base_path);
+        let mut ok = !cur.children.is_empty();
+        for _ in 0..level {
+            if cur.children.is_empty() {
+                ok = false;
+                break;
+            }
+            let name = cur.choose::<H>(&path, pgid, index);
+            if !path.is_empty() {
+                path += "/";
+            }
+            path += name;
+            cur = cur.children[name].as_ref();
+        }
+        index += 1;
+        if ok && !result.iter().any(|(_, p)| *p == path) {
+            result.push((cur, path));
+        }
+    }
+    result
+}
+
+/// Descend from `bucket` to a leaf, retrying locally (bounded) on an `out` or
+/// already-used leaf, as [`Crush::select`] does for a single replica; unlike
+/// `select`, it returns `None` after four rejections instead of restarting
+/// from the root.
+fn draw_leaf<'a, H: CrushHasher>(
+    bucket: &'a Node,
+    bucket_path: &str,
+    pgid: u32,
+    start_index: u32,
+    exclude: &[String],
+) -> Option<(&'a Node, String)> {
+    let mut node = bucket;
+    let mut path = String::from(bucket_path);
+    let mut index = start_index;
+    let mut local_failure = 0;
+    loop {
+        if node.children.is_empty() {
+            return if !node.out && !exclude.contains(&path) {
+                Some((node, path))
+            } else {
+                None
+            };
+        }
+        let name = node.choose::<H>(&path, pgid, index);
+        let mut candidate = path.clone();
+        candidate += "/";
+        candidate += name;
+        let child: &Node = node.children[name].as_ref();
+        if !child.children.is_empty() {
+            node = child;
+            path = candidate;
+            continue;
+        }
+        if !child.out && !exclude.contains(&candidate) {
+            return Some((child, candidate));
+        }
+        index += 1;
+        local_failure += 1;
+        if local_failure > 3 {
+            return None;
+        }
+    }
+}
+
+/// A single step in a [`Rule`]'s placement bytecode.
+#[derive(Clone)]
+enum Step {
+    /// Set the current working bucket(s) to the subtree rooted at `path`.
+    Take(String),
+    /// Pick `count` distinct buckets `level` steps below each working bucket.
+    /// `level` is relative to wherever `Take` (or the previous step) landed,
+    /// not an absolute named hierarchy tier: `Node` carries no type/level
+    /// metadata for this to key off of.
+    Choose { count: u32, level: usize },
+    /// Like `Choose`, but also draws one distinct leaf under each chosen bucket.
+    ChooseLeaf { count: u32, level: usize },
+    /// Flush the working bucket(s) into the result.
+    Emit,
+}
+
+/// An ordered list of placement steps, built with a small fluent builder.
+///
+/// Mirrors Ceph's CRUSH rules: `take` selects a subtree (so heterogeneous
+/// device classes can be targeted independently), `choose`/`chooseleaf` spread
+/// replicas across a failure-domain level, and `emit` flushes the result.
+///
+/// Unlike Ceph, `level` in `choose`/`chooseleaf` is a depth *relative* to the
+/// current working bucket(s) (however `take` or the previous step landed),
+/// not an absolute named tier like `rack` or `host` -- `Node` has no
+/// type/level metadata to resolve an absolute tier against. Chaining
+/// `choose().choose()` measures each `level` from where the prior step left
+/// off, not from the root.
+#[derive(Clone, Default)]
+pub struct Rule {
+    steps: Vec<Step>,
+}
+
+impl Rule {
+    /// Start building an empty rule.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the current bucket to the subtree rooted at `path` (e.g. `""` for root).
+    pub fn take(mut self, path: impl Into<String>) -> Self {
+        self.steps.push(Step::Take(path.into()));
+        self
+    }
+
+    /// Pick `count` distinct buckets `level` steps below the current bucket.
+    /// `level` is relative to the current working bucket(s), not an absolute
+    /// named hierarchy tier; see the [`Rule`] docs.
+    pub fn choose(mut self, count: u32, level: usize) -> Self {
+        self.steps.push(Step::Choose { count, level });
+        self
+    }
+
+    /// Pick `count` distinct buckets `level` steps below the current bucket,
+    /// then draw one leaf under each. `level` is relative, as in
+    /// [`Rule::choose`].
+    pub fn chooseleaf(mut self, count: u32, level: usize) -> Self {
+        self.steps.push(Step::ChooseLeaf { count, level });
+        self
+    }
+
+    /// Flush the current working bucket(s) into the result.
+    pub fn emit(mut self) -> Self {
+        self.steps.push(Step::Emit);
+        self
     }
 }
 
@@ -100,12 +750,15 @@ impl Node {
     /// Add weight to a node.
     fn add_weight(&mut self, path: &str, weight: i64) {
         self.weight = (self.weight as i64 + weight) as u64;
+        *self.tree_cache.lock() = None;
         if path.is_empty() {
             return;
         }
         let (name, suffix) = path.split_once('/').unwrap_or((path, ""));
+        // clone-on-write: only this child along `path` is made unique, siblings
+        // (and their subtrees) stay shared with any outstanding `CrushSnapshot`.
         let child = self.children.entry(name.into()).or_default();
-        child.add_weight(suffix, weight);
+        Arc::make_mut(child).add_weight(suffix, weight);
     }
 
     /// Get a node by path.
@@ -117,38 +770,75 @@ impl Node {
         self.children[name].get(suffix)
     }
 
-    /// Get a mutable node by path.
+    /// Get a mutable node by path. Clone-on-write: see [`Node::add_weight`].
     fn get_mut(&mut self, path: &str) -> &mut Self {
         if path.is_empty() {
             return self;
         }
         let (name, suffix) = path.split_once('/').unwrap_or((path, ""));
-        self.children.get_mut(name).unwrap().get_mut(suffix)
+        let child = self.children.get_mut(name).unwrap();
+        Arc::make_mut(child).get_mut(suffix)
+    }
+
+    /// Choose a child accroding to key and index, dispatching to this
+    /// node's configured [`BucketKind`]. `bucket_path` identifies this bucket
+    /// itself (the path from the root to `self`), so that same-shaped sibling
+    /// buckets (e.g. two racks with the same host count) still draw
+    /// independently instead of picking the same relative child.
+    fn choose<H: CrushHasher>(&self, bucket_path: &str, key: u32, index: u32) -> &str {
+        match self.bucket {
+            BucketKind::Straw2 => self.choose_straw2::<H>(key, index),
+            BucketKind::Tree => self.choose_tree::<H>(bucket_path, key, index),
+        }
     }
 
-    /// Choose a child accroding to key and index.
-    fn choose(&self, key: u32, index: u32) -> &str {
+    /// Choose a child by scanning every child's straw2 draw (exact, O(n)).
+    fn choose_straw2<H: CrushHasher>(&self, key: u32, index: u32) -> &str {
         self.children
             .iter()
             .map(|(name, child)| {
-                let mut hasher = AHasher::default();
-                name.hash(&mut hasher);
-                key.hash(&mut hasher);
-                index.hash(&mut hasher);
-
-                let w = LN_TABLE[(hasher.finish() & 65535) as usize] / child.weight;
+                let w = LN_TABLE[(H::hash(name, key, index) & 65535) as usize] / child.weight;
                 (name, w)
             })
             .min_by_key(|(_, w)| *w)
             .unwrap()
             .0
     }
+
+    /// Choose a child by descending the cached weighted binary tree (O(log n)).
+    ///
+    /// `bucket_path` (this bucket's own path from the root) is folded into
+    /// every level's draw, the way Ceph's tree bucket mixes in `bucket->hash`:
+    /// without it, two buckets with the same shape (same child count/weights)
+    /// would descend identically for a given `(key, index)`, correlating
+    /// placement across same-shaped siblings instead of drawing independently.
+    fn choose_tree<H: CrushHasher>(&self, bucket_path: &str, key: u32, index: u32) -> &str {
+        let mut cache_guard = self.tree_cache.lock();
+        if cache_guard.is_none() {
+            *cache_guard = Some(TreeCache::build(&self.children));
+        }
+        let cache = cache_guard.as_ref().unwrap();
+
+        let mut node_id = 1usize;
+        while node_id < cache.n {
+            let (left, right) = (2 * node_id, 2 * node_id + 1);
+            let (lw, rw) = (cache.weight[left], cache.weight[right]);
+            let total = lw + rw;
+            let draw = if total == 0 {
+                0
+            } else {
+                H::hash(&format!("{bucket_path}:{node_id}"), key, index) as u64 % total
+            };
+            node_id = if draw < lw { left } else { right };
+        }
+        let name = &cache.order[node_id - cache.n];
+        self.children.get_key_value(name.as_str()).unwrap().0
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use alloc::format;
     use rand::Rng;
 
     /// Generate a 9*9*9*10 cluster map.
@@ -196,6 +886,178 @@ mod tests {
         }
     }
 
+    /// test that the default hasher is deterministic across instances
+    #[test]
+    fn jenkins_hash_is_stable() {
+        assert_eq!(JenkinsHash::hash("osd.0", 42, 0), JenkinsHash::hash("osd.0", 42, 0));
+        assert_ne!(JenkinsHash::hash("osd.0", 42, 0), JenkinsHash::hash("osd.1", 42, 0));
+        assert_ne!(JenkinsHash::hash("osd.0", 42, 0), JenkinsHash::hash("osd.0", 42, 1));
+    }
+
+    /// test that a take/chooseleaf/emit rule spreads leaves across hosts
+    #[test]
+    fn select_with_rule_chooseleaf() {
+        let crush = gen_test_map();
+        let rule = Rule::new().take("").chooseleaf(3, 3).emit();
+        for pgid in 0..1000 {
+            let targets = crush.select_with_rule(pgid, &rule);
+            assert_eq!(targets.len(), 3, "pgid {pgid} should get 3 targets");
+            let hosts: alloc::collections::BTreeSet<&str> = targets
+                .iter()
+                .map(|t| {
+                    let mut parts = t.splitn(4, '/');
+                    parts.next().unwrap();
+                    parts.next().unwrap();
+                    parts.next().unwrap()
+                })
+                .collect();
+            assert_eq!(hosts.len(), 3, "pgid {pgid} targets {targets:?} share a host");
+        }
+    }
+
+    /// test that staged changes only take effect once committed, and that diff
+    /// reports exactly the PGs that moved
+    #[test]
+    fn stage_commit_and_diff() {
+        let mut crush = gen_test_map();
+        let before = crush.clone();
+        assert_eq!(crush.version(), 0);
+
+        crush.stage_weight("row.0/rack.0/host.0/osd.0", 100);
+        crush.stage_inout("row.1/rack.10/host.91", true);
+        for pgid in 0..1000 {
+            assert_eq!(crush.locate(pgid), before.locate(pgid), "staged change moved pgid {pgid} early");
+        }
+
+        crush.commit();
+        assert_eq!(crush.version(), 1);
+
+        let moved = before.diff(&crush, 0..1000);
+        assert!(!moved.is_empty(), "commit should move some PGs");
+        let expected = (0..1000)
+            .filter(|&pgid| before.locate(pgid) != crush.locate(pgid))
+            .count();
+        assert_eq!(moved.len(), expected, "diff missed some moved PGs");
+        for pg_move in &moved {
+            assert_eq!(pg_move.from, before.locate(pg_move.pgid));
+            assert_eq!(pg_move.to, crush.locate(pg_move.pgid));
+        }
+    }
+
+    /// test that a `Crush` map survives a serialize/deserialize round-trip:
+    /// version, weights, in/out state, and placement must all come back
+    /// identical, since shipping the map between nodes is the whole point.
+    #[test]
+    fn serde_round_trip() {
+        let mut crush = gen_test_map();
+        crush.stage_weight("row.0/rack.0/host.0/osd.0", 100);
+        crush.stage_inout("row.1/rack.10/host.91", true);
+        crush.commit();
+
+        let json = serde_json::to_string(&crush).unwrap();
+        let restored: Crush = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.version(), crush.version());
+        assert_eq!(restored.total_weight(), crush.total_weight());
+        assert_eq!(
+            restored.get_weight("row.0/rack.0/host.0/osd.0"),
+            crush.get_weight("row.0/rack.0/host.0/osd.0")
+        );
+        assert_eq!(
+            restored.get_inout("row.1/rack.10/host.91"),
+            crush.get_inout("row.1/rack.10/host.91")
+        );
+        for pgid in 0..1000 {
+            assert_eq!(restored.locate(pgid), crush.locate(pgid));
+            assert_eq!(
+                restored.select_failure_domain(pgid, 3, 2),
+                crush.select_failure_domain(pgid, 3, 2)
+            );
+        }
+    }
+
+    /// test that a snapshot keeps serving the old placements after the live
+    /// map is mutated (copy-on-write should not disturb outstanding readers)
+    #[test]
+    fn snapshot_is_pinned_across_mutation() {
+        let mut crush = gen_test_map();
+        let snap = crush.snapshot();
+        assert_eq!(snap.version(), 0);
+
+        crush.add_weight("row.0/rack.0/host.0/osd.0", 100);
+        crush.set_inout("row.1/rack.10/host.91", true);
+
+        let mut moved = 0;
+        for pgid in 0..1000 {
+            if crush.locate(pgid) != snap.locate(pgid) {
+                moved += 1;
+            }
+        }
+        assert!(moved > 0, "mutating crush should move some PGs off the snapshot");
+
+        // taking a fresh snapshot after the mutation agrees with the live map
+        let snap2 = crush.snapshot();
+        assert_eq!(snap2.version(), 0, "snapshot version reflects uncommitted add_weight/set_inout");
+        for pgid in 0..1000 {
+            assert_eq!(snap2.locate(pgid), crush.locate(pgid));
+        }
+    }
+
+    /// test that a tree bucket distributes placements as evenly as straw2
+    #[test]
+    fn tree_bucket_balance() {
+        let mut crush = gen_test_map();
+        crush.set_bucket_kind("", BucketKind::Tree);
+        let mut count = BTreeMap::<String, u32>::new();
+        let n = 1000000;
+        for i in 0..n {
+            let path = crush.locate(i);
+            *count.entry(path).or_default() += 1;
+        }
+        let avg = n / (9 * 9 * 9 * 10);
+        for (name, count) in count {
+            let range = avg / 2..avg * 2;
+            assert!(
+                range.contains(&count),
+                "path {name:?} count {count} out of range {range:?}"
+            );
+        }
+    }
+
+    /// Two sibling `Tree` buckets with the same shape (same child count and
+    /// weights) must still draw independently: before bucket identity was
+    /// folded into `choose_tree`'s hash, they picked the identical relative
+    /// child position for every `(pgid, index)`.
+    #[test]
+    fn tree_bucket_siblings_draw_independently() {
+        let mut crush = Crush::<JenkinsHash>::default();
+        for rack in ["a", "b"] {
+            for host in 0..8 {
+                crush.add_weight(&format!("{rack}/host.{host}"), 1);
+            }
+        }
+        crush.set_bucket_kind("a", BucketKind::Tree);
+        crush.set_bucket_kind("b", BucketKind::Tree);
+
+        let node_a = crush.root.get("a");
+        let node_b = crush.root.get("b");
+
+        let n = 200;
+        let mut same = 0;
+        for pgid in 0..n {
+            let pick_a = node_a.choose::<JenkinsHash>("a", pgid, 0);
+            let pick_b = node_b.choose::<JenkinsHash>("b", pgid, 0);
+            if pick_a == pick_b {
+                same += 1;
+            }
+        }
+        assert!(
+            same < n / 2,
+            "same-shaped sibling Tree buckets picked the same child position \
+             {same}/{n} times -- draws are correlated instead of independent"
+        );
+    }
+
     /// test distribute on insert
     #[test]
     fn move_factor_add() {
@@ -253,4 +1115,49 @@ mod tests {
             (move_count as f32) / (n as f32 / (crush0.total_weight() / shift_weight) as f32);
         assert!(move_fator < 1.5, "move factor {move_fator} should < 1.5");
     }
+
+    /// test that failure-domain selection spreads replicas across racks
+    #[test]
+    fn select_failure_domain_spreads_across_racks() {
+        let crush = gen_test_map();
+        for pgid in 0..1000 {
+            let targets = crush.select_failure_domain(pgid, 3, 2);
+            assert_eq!(targets.len(), 3, "pgid {pgid} should get 3 targets");
+            let racks: alloc::collections::BTreeSet<(&str, &str)> = targets
+                .iter()
+                .map(|t| {
+                    let mut parts = t.splitn(3, '/');
+                    (parts.next().unwrap(), parts.next().unwrap())
+                })
+                .collect();
+            assert_eq!(racks.len(), 3, "pgid {pgid} targets {targets:?} share a rack");
+        }
+    }
+
+    /// A degraded map (one of only two hosts mostly `out`) still yields a
+    /// full `num`-target result for every pgid: a reject inside the chosen
+    /// domain must retry locally within that domain/leaf before giving up on
+    /// it, not discard the whole draw and restart from root, which used to
+    /// starve the budget before exhausting the degraded host's one healthy osd.
+    #[test]
+    fn select_failure_domain_retries_locally_on_degraded_map() {
+        let mut crush = Crush::<JenkinsHash>::default();
+        for host in 0..2 {
+            for osd in 0..9 {
+                crush.add_weight(&format!("host.{host}/osd.{osd}"), 1);
+            }
+        }
+        for osd in 0..8 {
+            crush.set_inout(&format!("host.0/osd.{osd}"), true);
+        }
+
+        for pgid in 0..2000 {
+            let targets = crush.select_failure_domain(pgid, 2, 1);
+            assert_eq!(
+                targets.len(),
+                2,
+                "pgid {pgid} should still get 2 targets from a degraded map"
+            );
+        }
+    }
 }